@@ -0,0 +1,95 @@
+use super::Pos;
+use anyhow::{anyhow, Context, Result};
+use geocoding::{Forward, Openstreetmap, Point};
+use std::fmt;
+use std::time::Duration;
+
+// Matches the endpoint geocoding::Openstreetmap::new() would otherwise use
+const NOMINATIM_ENDPOINT: &str = "https://nominatim.openstreetmap.org/search";
+
+pub type DynGeocoder = Box<dyn Geocoder + Send + Sync>;
+
+/// Geocoder is responsible for resolving a free-text address to coordinates.
+/// Kept behind a trait, like `Provider`, so it can be faked in tests.
+#[rocket::async_trait]
+pub trait Geocoder {
+    async fn resolve(&self, address: &str) -> Result<Pos>;
+}
+
+/// Marker error for "no match for this address", as opposed to a transient
+/// or internal failure resolving it. Callers can downcast to tell the two
+/// apart, e.g. to report a 404 instead of a 500.
+#[derive(Debug)]
+pub struct AddressNotFound;
+
+impl fmt::Display for AddressNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "address not found")
+    }
+}
+
+impl std::error::Error for AddressNotFound {}
+
+/// OpenStreetMap's Nominatim forward-geocoder, used as a fallback for places
+/// missing from CITIES
+pub struct Osm;
+
+impl Osm {
+    pub fn new() -> DynGeocoder {
+        Box::new(Osm)
+    }
+}
+
+#[rocket::async_trait]
+impl Geocoder for Osm {
+    async fn resolve(&self, address: &str) -> Result<Pos> {
+        let query = address.to_owned();
+        let points: Vec<Point<f64>> = tokio::task::spawn_blocking(move || {
+            // Like every other outbound call in this crate, bound how long a
+            // slow/unresponsive upstream can tie up a thread
+            let client = reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("build geocoding http client");
+
+            Openstreetmap::new_with_endpoint(NOMINATIM_ENDPOINT.to_owned(), client).forward(&query)
+        })
+        .await
+        .context("geocoding task panicked")?
+        .map_err(|e| anyhow!("error resolving address: {}", e))?;
+
+        let point = points.into_iter().next().ok_or(AddressNotFound)?;
+
+        Ok(Pos::new(point.y() as f32, point.x() as f32))
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+
+    struct FakeGeocoder(Result<Pos>);
+
+    #[rocket::async_trait]
+    impl Geocoder for FakeGeocoder {
+        async fn resolve(&self, _address: &str) -> Result<Pos> {
+            match &self.0 {
+                Ok(pos) => Ok(*pos),
+                Err(e) if e.is::<AddressNotFound>() => Err(AddressNotFound.into()),
+                Err(e) => Err(anyhow!(e.to_string())),
+            }
+        }
+    }
+
+    pub fn stub(pos: Pos) -> DynGeocoder {
+        Box::new(FakeGeocoder(Ok(pos)))
+    }
+
+    pub fn erroneous(e: &str) -> DynGeocoder {
+        Box::new(FakeGeocoder(Err(anyhow!(e.to_owned()))))
+    }
+
+    pub fn not_found() -> DynGeocoder {
+        Box::new(FakeGeocoder(Err(AddressNotFound.into())))
+    }
+}
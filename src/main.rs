@@ -1,5 +1,8 @@
 mod provider;
-use provider::{accu, owm};
+use provider::{accu, metno, owm, Metric};
+
+mod geocoder;
+use geocoder::{AddressNotFound, Osm};
 
 mod service;
 use service::{Pos, Service, CITIES};
@@ -14,6 +17,30 @@ use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
 
 use std::env;
+use std::time::Duration;
+
+// How long a cached forecast is served without any refresh, and the hard
+// cutoff past which it's never served even stale
+const FRESH_FOR: Duration = Duration::from_secs(60 * 60);
+const EXPIRE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Metrics requested via the `metrics` query param, e.g. `metrics=temp,uv`.
+/// Defaults to just the temperature to preserve the original behavior.
+fn parse_metrics(raw: Option<String>) -> Result<Vec<Metric>, StatusError> {
+    let raw = match raw {
+        Some(raw) => raw,
+        None => return Ok(vec![Metric::Temperature]),
+    };
+
+    raw.split(',')
+        .map(|metric| {
+            metric.trim().parse().map_err(|_| StatusError {
+                code: 400,
+                error: format!("unknown metric: {}", metric),
+            })
+        })
+        .collect()
+}
 
 #[get("/")]
 fn index() -> Status {
@@ -36,17 +63,28 @@ impl<'r> response::Responder<'r, 'static> for StatusError {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 struct Current {
     pos: (f32, f32),
-    temp: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temp: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    precipitation: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uv: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aqi: Option<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
 }
 
-#[get("/current?<city>&<country>&<day>")]
+#[get("/current?<city>&<country>&<address>&<metrics>&<day>")]
 async fn current(
     service: State<'_, Service>,
-    country: String,
-    city: String,
+    country: Option<String>,
+    city: Option<String>,
+    address: Option<String>,
+    metrics: Option<String>,
     day: Option<usize>,
 ) -> Result<Json<Current>, StatusError> {
     let day = day.unwrap_or(0);
@@ -57,56 +95,126 @@ async fn current(
         });
     }
 
-    let (pos, forecast) = fetch(&service, &country, &city).await?;
+    let metrics = parse_metrics(metrics)?;
+    let (pos, by_metric, warnings) = fetch(&service, country, city, address, &metrics).await?;
 
-    Ok(Json(Current {
+    let mut current = Current {
         pos: pos.as_lat_lon(),
-        temp: forecast[day],
-    }))
+        warnings,
+        ..Default::default()
+    };
+
+    for (metric, series) in by_metric {
+        let value = series[day];
+        match metric {
+            Metric::Temperature => current.temp = Some(value),
+            Metric::Precipitation => current.precipitation = Some(value),
+            Metric::Uv => current.uv = Some(value),
+            Metric::Aqi => current.aqi = Some(value),
+        }
+    }
+
+    Ok(Json(current))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 struct Forecast {
     pos: (f32, f32),
-    forecast: [f32; 5],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temp: Option<[f32; 5]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    precipitation: Option<[f32; 5]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uv: Option<[f32; 5]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aqi: Option<[f32; 5]>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
 }
 
-#[get("/forecast?<city>&<country>")]
+#[get("/forecast?<city>&<country>&<address>&<metrics>")]
 async fn forecast(
     service: State<'_, Service>,
-    country: String,
-    city: String,
+    country: Option<String>,
+    city: Option<String>,
+    address: Option<String>,
+    metrics: Option<String>,
 ) -> Result<Json<Forecast>, StatusError> {
-    let (pos, forecast) = fetch(&service, &country, &city).await?;
+    let metrics = parse_metrics(metrics)?;
+    let (pos, by_metric, warnings) = fetch(&service, country, city, address, &metrics).await?;
 
-    Ok(Json(Forecast {
+    let mut forecast = Forecast {
         pos: pos.as_lat_lon(),
-        forecast,
-    }))
+        warnings,
+        ..Default::default()
+    };
+
+    for (metric, series) in by_metric {
+        match metric {
+            Metric::Temperature => forecast.temp = Some(series),
+            Metric::Precipitation => forecast.precipitation = Some(series),
+            Metric::Uv => forecast.uv = Some(series),
+            Metric::Aqi => forecast.aqi = Some(series),
+        }
+    }
+
+    Ok(Json(forecast))
 }
 
-// Tiny service request helper
+// Tiny service request helper, accepts either a known city/country pair or a
+// free-text address resolved through the geocoding fallback, and fetches
+// every requested metric for the resolved position in a single call. Warnings
+// collect which providers degraded, even though the overall request still
+// succeeded. A metric nothing could supply is simply left out.
 async fn fetch(
     service: &Service,
-    country: &str,
-    city: &str,
-) -> Result<(Pos, [f32; 5]), StatusError> {
-    let coordinates = CITIES
-        .find(&country, &city.to_lowercase())
-        .ok_or(StatusError {
-            code: 404,
-            error: "City not found".to_owned(),
-        })?;
-
-    match service.forecast(coordinates).await {
-        Ok(resp) => Ok((coordinates, resp)),
-        Err(err) => {
-            Err(StatusError {
-                code: 500,
-                error: format!("{:#}", err), // Print full error chain
+    country: Option<String>,
+    city: Option<String>,
+    address: Option<String>,
+    metrics: &[Metric],
+) -> Result<(Pos, Vec<(Metric, [f32; 5])>, Vec<String>), StatusError> {
+    let coordinates = match (country, city, address) {
+        (Some(country), Some(city), _) => {
+            CITIES
+                .find(&country, &city.to_lowercase())
+                .ok_or(StatusError {
+                    code: 404,
+                    error: "City not found".to_owned(),
+                })?
+        }
+        (_, _, Some(address)) => service.resolve_address(&address).await.map_err(|err| {
+            // Only a genuine no-match is a 404; a geocoder outage or panic
+            // is the service's fault, not the caller's
+            let code = if err.downcast_ref::<AddressNotFound>().is_some() {
+                404
+            } else {
+                500
+            };
+
+            StatusError {
+                code,
+                error: format!("{:#}", err),
+            }
+        })?,
+        _ => {
+            return Err(StatusError {
+                code: 400,
+                error: "either city and country, or address, must be given".to_owned(),
             })
         }
-    }
+    };
+
+    let (result, warnings) = service.forecast(coordinates).await.map_err(|err| StatusError {
+        code: 500,
+        error: format!("{:#}", err), // Print full error chain
+    })?;
+
+    let by_metric = metrics
+        .iter()
+        .filter_map(|&metric| result.get(metric).map(|series| (metric, series)))
+        .collect();
+
+    Ok((coordinates, by_metric, warnings))
 }
 
 #[rocket::main]
@@ -117,7 +225,15 @@ async fn main() -> Result<(), rocket::error::Error> {
     let token = env::var("ACCU_TOKEN").expect("ACCU_TOKEN env");
     let prov2 = accu::AccuWeather::new(token);
 
-    let service = Service::new(Vec::from([prov1, prov2]));
+    let user_agent = env::var("MET_USER_AGENT").expect("MET_USER_AGENT env");
+    let prov3 = metno::MetNo::new(user_agent);
+
+    let service = Service::new(
+        Vec::from([prov1, prov2, prov3]),
+        Osm::new(),
+        FRESH_FOR,
+        EXPIRE_AFTER,
+    );
 
     rocket::ignite()
         .mount("/", routes![index, current, forecast])
@@ -131,6 +247,7 @@ async fn main() -> Result<(), rocket::error::Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use geocoder::fake;
     use provider::fake::{erroneous, stub};
     use rocket::local::blocking::Client;
     use serde_json::Value;
@@ -139,8 +256,16 @@ mod tests {
         ($expected:ty, $url:expr) => {
             request!($expected, $url, Vec::from([stub(2.0), stub(4.0)]))
         };
-        ($expected:ty, $url:expr, $providers:expr) => {{
-            let service = Service::new(Vec::from($providers));
+        ($expected:ty, $url:expr, $providers:expr) => {
+            request!(
+                $expected,
+                $url,
+                $providers,
+                fake::erroneous("geocoder shouldn't be called")
+            )
+        };
+        ($expected:ty, $url:expr, $providers:expr, $geocoder:expr) => {{
+            let service = Service::new(Vec::from($providers), $geocoder, FRESH_FOR, EXPIRE_AFTER);
 
             let rocket = rocket::ignite()
                 .manage(service)
@@ -170,7 +295,17 @@ mod tests {
 
         assert_eq!(status, Status::Ok);
         assert_eq!(response.pos, (41.85003, -87.65005));
-        assert_eq!(response.forecast, [3.0, 4.0, 5.0, 6.0, 7.0]);
+        assert_eq!(response.temp, Some([3.0, 4.0, 5.0, 6.0, 7.0]));
+    }
+
+    #[test]
+    fn forecast_metrics_subset() {
+        let (status, response) =
+            request!(Forecast, "/forecast?country=US&city=Chicago&metrics=uv");
+
+        assert_eq!(status, Status::Ok);
+        assert!(response.temp.is_none());
+        assert!(response.uv.is_some());
     }
 
     #[test]
@@ -188,11 +323,22 @@ mod tests {
 
         assert_eq!(status, Status::Ok);
         assert_eq!(response.pos, (55.75222, 37.61556));
-        assert_eq!(response.temp, 3.0);
+        assert_eq!(response.temp, Some(3.0));
 
         let (status, response) = request!(Current, "/current?country=RU&city=Moscow&day=1");
         assert_eq!(status, Status::Ok);
-        assert_eq!(response.temp, 4.0);
+        assert_eq!(response.temp, Some(4.0));
+    }
+
+    #[test]
+    fn current_without_city_or_address_is_rejected() {
+        let (status, response) = request!(StatusError, "/current");
+
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(
+            response.error,
+            "either city and country, or address, must be given"
+        );
     }
 
     #[test]
@@ -202,6 +348,32 @@ mod tests {
         assert_eq!(response.error, "can\'t see further than 5 days");
     }
 
+    #[test]
+    fn address_not_found_is_reported_as_404() {
+        let (status, response) = request!(
+            StatusError,
+            "/current?address=Nowhere",
+            [stub(2.0)],
+            fake::not_found()
+        );
+
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(response.code, 404);
+    }
+
+    #[test]
+    fn geocoder_outage_is_reported_as_500_not_404() {
+        let (status, response) = request!(
+            StatusError,
+            "/current?address=Nowhere",
+            [stub(2.0)],
+            fake::erroneous("nominatim timed out")
+        );
+
+        assert_eq!(status, Status::InternalServerError);
+        assert_eq!(response.code, 500);
+    }
+
     #[test]
     fn error_propagation() {
         let (status, response) = request!(
@@ -213,7 +385,23 @@ mod tests {
         assert_eq!(status, Status::InternalServerError);
         assert_eq!(
             response.error,
-            "error while fetching forecast: something bad happened"
+            "every provider failed: fake: request failed"
         );
     }
+
+    #[test]
+    fn degrades_instead_of_failing_when_one_provider_survives() {
+        let (status, response) = request!(
+            Current,
+            "/current?country=DE&city=Berlin",
+            [stub(2.0), erroneous("rate limited")]
+        );
+
+        assert_eq!(status, Status::Ok);
+        assert_eq!(response.temp, Some(2.0));
+        assert_eq!(response.warnings.len(), 1);
+        // Warnings must never echo the raw upstream error - it may carry a
+        // provider API key in its request URL
+        assert_eq!(response.warnings[0], "fake: request failed");
+    }
 }
@@ -2,14 +2,68 @@ use super::Pos;
 use anyhow::{anyhow, Context, Result};
 use reqwest;
 use serde::Deserialize;
+use std::str::FromStr;
 use std::time::Duration;
 
 pub type DynProvider = Box<dyn Provider + Send + Sync>;
 
-/// Provider is responsible for fetching weekly weather forecast from its source
+/// A single weather metric that can be requested from a provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Metric {
+    Temperature,
+    Precipitation,
+    Uv,
+    Aqi,
+}
+
+impl FromStr for Metric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "temp" => Ok(Metric::Temperature),
+            "precip" => Ok(Metric::Precipitation),
+            "uv" => Ok(Metric::Uv),
+            "aqi" => Ok(Metric::Aqi),
+            other => Err(anyhow!("unknown metric: {}", other)),
+        }
+    }
+}
+
+/// Every metric a single provider response can carry, fetched together in one
+/// upstream call. A `None` field means this provider has no data for that
+/// metric, rather than that the call failed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricSet {
+    pub temperature: Option<[f32; 5]>,
+    /// Chance of precipitation as a 0-1 fraction. Providers that report this
+    /// natively as a percentage must normalize before populating this field,
+    /// since averaging across providers assumes a common scale.
+    pub precipitation: Option<[f32; 5]>,
+    pub uv: Option<[f32; 5]>,
+    pub aqi: Option<[f32; 5]>,
+}
+
+impl MetricSet {
+    pub fn get(&self, metric: Metric) -> Option<[f32; 5]> {
+        match metric {
+            Metric::Temperature => self.temperature,
+            Metric::Precipitation => self.precipitation,
+            Metric::Uv => self.uv,
+            Metric::Aqi => self.aqi,
+        }
+    }
+}
+
+/// Provider is responsible for fetching weekly weather forecast from its source.
+/// A single `fetch` call returns every metric the provider can supply, so
+/// requesting several metrics at once doesn't cost several upstream calls.
 #[rocket::async_trait]
 pub trait Provider {
-    async fn fetch(&self, pos: Pos) -> Result<[f32; 5]>;
+    /// Short, stable name used to identify this provider in degradation warnings
+    fn name(&self) -> &'static str;
+
+    async fn fetch(&self, pos: Pos) -> Result<MetricSet>;
 }
 
 // https://openweathermap.org/api/one-call-api
@@ -37,6 +91,8 @@ pub mod owm {
     #[derive(Deserialize)]
     struct Daily {
         temp: Temp,
+        pop: f32,
+        uvi: f32,
     }
 
     #[derive(Deserialize)]
@@ -52,7 +108,11 @@ pub mod owm {
 
     #[rocket::async_trait]
     impl Provider for OWM {
-        async fn fetch(&self, pos: Pos) -> Result<[f32; 5]> {
+        fn name(&self) -> &'static str {
+            "openweathermap"
+        }
+
+        async fn fetch(&self, pos: Pos) -> Result<MetricSet> {
             let pos = pos.as_lat_lon();
 
             let request = reqwest::Client::new()
@@ -77,13 +137,23 @@ pub mod owm {
                 .await
                 .context("error parsing response")?;
 
-            let mut result = [0.0; 5];
+            let mut temperature = [0.0; 5];
+            let mut precipitation = [0.0; 5];
+            let mut uv = [0.0; 5];
 
             for (i, day) in response.daily.iter().take(5).enumerate() {
-                result[i] = (day.temp.day + day.temp.night) / 2.0;
+                temperature[i] = (day.temp.day + day.temp.night) / 2.0;
+                precipitation[i] = day.pop;
+                uv[i] = day.uvi;
             }
 
-            Ok(result)
+            // One-call's "daily" block has no air quality figures
+            Ok(MetricSet {
+                temperature: Some(temperature),
+                precipitation: Some(precipitation),
+                uv: Some(uv),
+                aqi: None,
+            })
         }
     }
 }
@@ -137,6 +207,14 @@ pub mod accu {
     pub struct DailyForecast {
         #[serde(rename = "Temperature")]
         temperature: Temperature,
+        #[serde(rename = "Day")]
+        day: Day,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Day {
+        #[serde(rename = "PrecipitationProbability")]
+        precipitation_probability: f32,
     }
 
     #[derive(Deserialize)]
@@ -155,7 +233,11 @@ pub mod accu {
 
     #[rocket::async_trait]
     impl Provider for AccuWeather {
-        async fn fetch(&self, pos: Pos) -> Result<[f32; 5]> {
+        fn name(&self) -> &'static str {
+            "accuweather"
+        }
+
+        async fn fetch(&self, pos: Pos) -> Result<MetricSet> {
             let key = self.search(pos).await?;
 
             let url = format!(
@@ -176,15 +258,134 @@ pub mod accu {
                 .await
                 .context("error parsing response")?;
 
-            let mut result = [0.0; 5];
+            let mut temperature = [0.0; 5];
+            let mut precipitation = [0.0; 5];
 
             for (i, day) in response.daily_forecasts.iter().enumerate() {
-                let min = day.temperature.minimum.value;
-                let max = day.temperature.maximum.value;
-                result[i] = (min + max) / 2.0;
+                temperature[i] = (day.temperature.minimum.value + day.temperature.maximum.value) / 2.0;
+                // AccuWeather reports this as a 0-100 percentage; MetricSet's
+                // precipitation is a 0-1 fraction, matching OWM's native scale
+                precipitation[i] = day.day.precipitation_probability / 100.0;
             }
 
-            Ok(result)
+            // Neither UV nor air quality are part of the daily forecast payload
+            Ok(MetricSet {
+                temperature: Some(temperature),
+                precipitation: Some(precipitation),
+                uv: None,
+                aqi: None,
+            })
+        }
+    }
+}
+
+// https://api.met.no/weatherapi/locationforecast/2.0/documentation
+// Free, token-free API, but rejects requests that don't identify themselves
+// with a descriptive User-Agent
+pub mod metno {
+    use super::*;
+    use chrono::{DateTime, Utc};
+
+    pub struct MetNo {
+        user_agent: String,
+    }
+
+    impl MetNo {
+        pub fn new(user_agent: String) -> DynProvider {
+            Box::new(MetNo { user_agent })
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Response {
+        properties: Properties,
+    }
+
+    #[derive(Deserialize)]
+    struct Properties {
+        timeseries: Vec<TimeseriesEntry>,
+    }
+
+    #[derive(Deserialize)]
+    struct TimeseriesEntry {
+        time: DateTime<Utc>,
+        data: TimeseriesData,
+    }
+
+    #[derive(Deserialize)]
+    struct TimeseriesData {
+        instant: Instant,
+    }
+
+    #[derive(Deserialize)]
+    struct Instant {
+        details: Details,
+    }
+
+    #[derive(Deserialize)]
+    struct Details {
+        air_temperature: f32,
+    }
+
+    #[rocket::async_trait]
+    impl Provider for MetNo {
+        fn name(&self) -> &'static str {
+            "met.no"
+        }
+
+        async fn fetch(&self, pos: Pos) -> Result<MetricSet> {
+            let pos = pos.as_lat_lon();
+
+            let response = reqwest::Client::new()
+                .get("https://api.met.no/weatherapi/locationforecast/2.0/compact")
+                .timeout(Duration::from_secs(10))
+                .header("User-Agent", &self.user_agent)
+                .query(&[("lat", pos.0), ("lon", pos.1)])
+                .send()
+                .await
+                .context("error requesting provider")?
+                .json::<Response>()
+                .await
+                .context("error parsing response")?;
+
+            let first_day = response
+                .properties
+                .timeseries
+                .first()
+                .map(|entry| entry.time.date())
+                .context("empty forecast")?;
+
+            // Reduce the sub-daily entries into five daily averages
+            let mut sums = [0.0; 5];
+            let mut counts = [0u32; 5];
+
+            for entry in &response.properties.timeseries {
+                let day_offset = (entry.time.date() - first_day).num_days();
+                if day_offset < 0 || day_offset as usize >= sums.len() {
+                    continue;
+                }
+
+                let day_offset = day_offset as usize;
+                sums[day_offset] += entry.data.instant.details.air_temperature;
+                counts[day_offset] += 1;
+            }
+
+            let mut temperature = [0.0; 5];
+            for (i, (sum, count)) in sums.iter().zip(counts.iter()).enumerate() {
+                if *count == 0 {
+                    return Err(anyhow!("not enough data for day {}", i));
+                }
+                temperature[i] = sum / *count as f32;
+            }
+
+            // Locationforecast only carries instant air temperature, none of
+            // the other metrics
+            Ok(MetricSet {
+                temperature: Some(temperature),
+                precipitation: None,
+                uv: None,
+                aqi: None,
+            })
         }
     }
 }
@@ -198,7 +399,11 @@ pub mod fake {
 
     #[rocket::async_trait]
     impl Provider for FakeProvider {
-        async fn fetch(&self, _pos: Pos) -> Result<[f32; 5]> {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        async fn fetch(&self, _pos: Pos) -> Result<MetricSet> {
             match self.0 {
                 Ok(n) => {
                     let mut result = [n; 5];
@@ -206,7 +411,12 @@ pub mod fake {
                         result[i] += i as f32
                     }
 
-                    anyhow::Result::Ok(result)
+                    anyhow::Result::Ok(MetricSet {
+                        temperature: Some(result),
+                        precipitation: Some(result),
+                        uv: Some(result),
+                        aqi: Some(result),
+                    })
                 }
                 Err(ref e) => Err(anyhow!(e.to_string())),
             }
@@ -220,4 +430,27 @@ pub mod fake {
     pub fn erroneous(e: &str) -> DynProvider {
         Box::new(FakeProvider(Err(anyhow!(e.to_owned()))))
     }
+
+    struct PrecipProvider(f32);
+
+    #[rocket::async_trait]
+    impl Provider for PrecipProvider {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        async fn fetch(&self, _pos: Pos) -> Result<MetricSet> {
+            Ok(MetricSet {
+                precipitation: Some([self.0; 5]),
+                ..Default::default()
+            })
+        }
+    }
+
+    /// A provider that only supplies a precipitation fraction, so tests can
+    /// mix providers with different precipitation readings independently of
+    /// the other metrics
+    pub fn precip_stub(fraction: f32) -> DynProvider {
+        Box::new(PrecipProvider(fraction))
+    }
 }
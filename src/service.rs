@@ -1,18 +1,17 @@
-use crate::provider::DynProvider;
+use crate::geocoder::DynGeocoder;
+use crate::provider::{DynProvider, MetricSet};
 use serde::Deserialize;
 
 use futures::future;
 use lazy_static::lazy_static;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{borrow::Cow, cmp, hash};
 use tokio::sync::RwLock;
 
-use chrono::offset::Local;
-use chrono::Date;
-
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Result};
 
 /// City location latitude and longitude pair with custom guarantees
 #[derive(Debug, Clone, Copy)]
@@ -36,6 +35,10 @@ impl cmp::PartialEq for Pos {
 impl cmp::Eq for Pos {}
 
 impl Pos {
+    pub(crate) fn new(lat: f32, lng: f32) -> Self {
+        Pos(lat, lng)
+    }
+
     pub fn as_lat_lon(&self) -> (f32, f32) {
         (self.0, self.1)
     }
@@ -77,118 +80,479 @@ impl CITIES {
 }
 
 pub struct Service {
-    providers: Vec<DynProvider>,
+    providers: Arc<Vec<DynProvider>>,
+    geocoder: DynGeocoder,
     cache: Arc<RwLock<HashMap<Pos, CacheEntry>>>,
+    // Positions currently being refreshed in the background, so a busy cache
+    // entry's stale window doesn't spawn a redundant fetch per request
+    refreshing: Arc<RwLock<HashSet<Pos>>>,
+    // Resolved addresses keyed on their normalized (trimmed, lowercased) form,
+    // so repeat lookups of the same place don't re-hit the geocoder
+    geocode_cache: Arc<RwLock<HashMap<String, Pos>>>,
+    // Entries younger than this are served as-is
+    fresh_for: Duration,
+    // Entries older than this are never served, even stale; fetched synchronously instead
+    expire_after: Duration,
 }
 
 struct CacheEntry {
-    date: Date<Local>,
-    forecast: [f32; 5],
+    fetched_at: Instant,
+    forecast: MetricSet,
+}
+
+// Accumulates per-provider metric series into a running sum, so several
+// metrics can be averaged together from one pass over the providers
+#[derive(Default)]
+struct Accumulator {
+    sum: [f32; 5],
+    count: u32,
+}
+
+impl Accumulator {
+    fn add(&mut self, series: [f32; 5]) {
+        for (i, t) in self.sum.iter_mut().enumerate() {
+            *t += series[i];
+        }
+        self.count += 1;
+    }
+
+    fn average(&self) -> Option<[f32; 5]> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let mut avg = self.sum;
+        for t in &mut avg {
+            *t /= self.count as f32;
+        }
+
+        Some(avg)
+    }
 }
 
 /// Serivce is responsible for computing aggregates and caching results
 impl Service {
-    pub fn new(providers: Vec<DynProvider>) -> Self {
+    pub fn new(
+        providers: Vec<DynProvider>,
+        geocoder: DynGeocoder,
+        fresh_for: Duration,
+        expire_after: Duration,
+    ) -> Self {
         if providers.len() == 0 {
             panic!("tried to initialize weather service with zero providers")
         }
 
+        if expire_after < fresh_for {
+            panic!("expire_after must be at least fresh_for")
+        }
+
         Self {
-            providers,
+            providers: Arc::new(providers),
+            geocoder,
             cache: Default::default(),
+            refreshing: Default::default(),
+            geocode_cache: Default::default(),
+            fresh_for,
+            expire_after,
+        }
+    }
+
+    /// Resolves a free-text address to coordinates via the geocoder, used as
+    /// a fallback for places missing from CITIES
+    pub async fn resolve_address(&self, address: &str) -> Result<Pos> {
+        let key = address.trim().to_lowercase();
+
+        if let Some(pos) = self.geocode_cache.read().await.get(&key) {
+            return Ok(*pos);
         }
+
+        let pos = self.geocoder.resolve(&key).await?;
+
+        self.geocode_cache.write().await.insert(key, pos);
+
+        Ok(pos)
     }
 
-    pub async fn forecast(&self, pos: Pos) -> Result<[f32; 5]> {
-        let rcache = self.cache.read().await;
+    /// Returns every metric the providers could supply for `pos`, along with
+    /// a warning per provider that failed but was outvoted by surviving ones
+    /// (empty on a fresh or stale cache hit, since nothing degraded this
+    /// particular request)
+    pub async fn forecast(&self, pos: Pos) -> Result<(MetricSet, Vec<String>)> {
+        if let Some(entry) = self.cache.read().await.get(&pos) {
+            let age = entry.fetched_at.elapsed();
+
+            if age < self.fresh_for {
+                return Ok((entry.forecast, Vec::new()));
+            }
 
-        if let Some(entry) = rcache.get(&pos) {
-            if entry.date == Local::today() {
-                return Ok(entry.forecast);
+            if age < self.expire_after {
+                // Stale-while-revalidate: serve what we have immediately and
+                // let a background task refresh the entry for next time
+                self.maybe_spawn_refresh(pos).await;
+                return Ok((entry.forecast, Vec::new()));
             }
         }
 
-        // Slow path
-        drop(rcache);
-        let result = self.fetch_forecast(pos).await?;
+        // No usable entry: the caller has to wait for a synchronous fetch
+        let (result, warnings) = Self::fetch_forecast(&self.providers, pos).await?;
+        self.store(pos, result).await;
 
+        Ok((result, warnings))
+    }
+
+    async fn store(&self, pos: Pos, forecast: MetricSet) {
         let entry = CacheEntry {
-            date: Local::today(),
-            forecast: result,
+            fetched_at: Instant::now(),
+            forecast,
         };
         self.cache.write().await.insert(pos, entry);
+    }
+
+    // Spawns a background refresh for `pos`, unless one is already in flight
+    async fn maybe_spawn_refresh(&self, pos: Pos) {
+        {
+            let mut refreshing = self.refreshing.write().await;
+            if !refreshing.insert(pos) {
+                // Someone else's refresh is already underway for this position
+                return;
+            }
+        }
+
+        let providers = Arc::clone(&self.providers);
+        let cache = Arc::clone(&self.cache);
+        let refreshing = Arc::clone(&self.refreshing);
+
+        tokio::spawn(async move {
+            // A failed refresh keeps serving the last good data rather than
+            // clobbering the cache entry with nothing
+            if let Ok((forecast, _warnings)) = Self::fetch_forecast(&providers, pos).await {
+                let entry = CacheEntry {
+                    fetched_at: Instant::now(),
+                    forecast,
+                };
+                cache.write().await.insert(pos, entry);
+            }
 
-        Ok(result)
+            refreshing.write().await.remove(&pos);
+        });
     }
 
-    async fn fetch_forecast(&self, pos: Pos) -> Result<[f32; 5]> {
-        let mut futures = Vec::with_capacity(self.providers.len());
+    async fn fetch_forecast(
+        providers: &[DynProvider],
+        pos: Pos,
+    ) -> Result<(MetricSet, Vec<String>)> {
+        let mut futures = Vec::with_capacity(providers.len());
 
         // Fetch data in parallel
-        for provider in &self.providers {
-            futures.push(provider.fetch(pos));
+        for provider in providers {
+            let name = provider.name();
+            futures.push(async move { (name, provider.fetch(pos).await) });
         }
 
-        let mut avg = [0.0; 5];
-
-        for result in future::join_all(futures).await {
-            let result = result.context("error while fetching forecast")?;
-
-            for (i, t) in avg.iter_mut().enumerate() {
-                *t += result[i];
+        let mut temperature = Accumulator::default();
+        let mut precipitation = Accumulator::default();
+        let mut uv = Accumulator::default();
+        let mut aqi = Accumulator::default();
+        let mut supported = 0;
+        let mut warnings = Vec::new();
+
+        for (name, result) in future::join_all(futures).await {
+            let result = match result {
+                Ok(result) => result,
+                Err(err) => {
+                    // The error chain from an upstream HTTP client can embed
+                    // the request URL, which may carry a provider API key in
+                    // its query string - log it server-side and only expose
+                    // a redacted message to callers
+                    eprintln!("{}: {:#}", name, err);
+                    warnings.push(format!("{}: request failed", name));
+                    continue;
+                }
+            };
+
+            supported += 1;
+            if let Some(series) = result.temperature {
+                temperature.add(series);
+            }
+            if let Some(series) = result.precipitation {
+                precipitation.add(series);
+            }
+            if let Some(series) = result.uv {
+                uv.add(series);
+            }
+            if let Some(series) = result.aqi {
+                aqi.add(series);
             }
         }
 
-        for t in &mut avg {
-            *t /= self.providers.len() as f32;
+        if supported == 0 {
+            return Err(anyhow!("every provider failed: {}", warnings.join("; ")));
         }
 
-        Ok(avg)
+        let forecast = MetricSet {
+            temperature: temperature.average(),
+            precipitation: precipitation.average(),
+            uv: uv.average(),
+            aqi: aqi.average(),
+        };
+
+        Ok((forecast, warnings))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::provider::fake::{erroneous, stub};
-    use chrono::Duration;
+    use crate::geocoder;
+    use crate::provider::fake::{erroneous, precip_stub, stub};
+
+    // Long enough that no test below can cross a threshold by accident
+    const FRESH_FOR: Duration = Duration::from_secs(3600);
+    const EXPIRE_AFTER: Duration = Duration::from_secs(7200);
+
+    fn service(providers: Vec<DynProvider>) -> Service {
+        Service::new(
+            providers,
+            geocoder::fake::erroneous("geocoder shouldn't be called"),
+            FRESH_FOR,
+            EXPIRE_AFTER,
+        )
+    }
+
+    // Polls `f` until it returns `Some`, rather than sleeping a fixed
+    // duration, so waiting on a background task isn't a CI flake risk
+    async fn poll_until<T>(mut f: impl FnMut() -> Option<T>) -> T {
+        for _ in 0..200 {
+            if let Some(value) = f() {
+                return value;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("timed out waiting for condition");
+    }
+
+    #[rocket::async_test]
+    async fn service_fetches_on_empty_cache() {
+        let service = service(Vec::from([stub(-8.0), stub(6.0)]));
+        let pos = CITIES.find("US", "chicago").unwrap();
+
+        let (forecast, warnings) = service.forecast(pos).await.unwrap();
+
+        assert_eq!(
+            forecast.temperature.unwrap().iter().sum::<f32>(),
+            5.0,
+            "average temperature did not equal expected"
+        );
+        assert!(warnings.is_empty());
+    }
 
     #[rocket::async_test]
-    async fn service() {
-        let service = Service::new(Vec::from([stub(-8.0), stub(6.0)]));
+    async fn service_serves_fresh_cache_without_refetching() {
+        let service = service(Vec::from([erroneous("shouldn't be called")]));
         let pos = CITIES.find("US", "chicago").unwrap();
 
-        // Test old caches are invalidated
         let entry = CacheEntry {
-            date: Local::today() - Duration::days(2),
-            forecast: [0.0; 5],
+            fetched_at: Instant::now(),
+            forecast: MetricSet {
+                temperature: Some([5.0; 5]),
+                ..Default::default()
+            },
         };
 
         service.cache.write().await.insert(pos, entry);
 
-        let avg = service.forecast(pos).await.unwrap();
+        let (forecast, warnings) = service.forecast(pos).await.unwrap();
 
         assert_eq!(
-            avg.iter().sum::<f32>(),
-            5.0,
-            "average temperature did not equal expected"
+            forecast.temperature.unwrap().iter().sum::<f32>(),
+            25.0,
+            "did not use cache"
         );
+        assert!(warnings.is_empty(), "erroneous provider should not be called");
     }
 
     #[rocket::async_test]
-    async fn service_caching() {
-        let service = Service::new(Vec::from([erroneous("shouldn't be called")]));
+    async fn service_serves_stale_entry_and_refreshes_in_background() {
+        let service = service(Vec::from([stub(9.0)]));
         let pos = CITIES.find("US", "chicago").unwrap();
 
         let entry = CacheEntry {
-            date: Local::today(),
-            forecast: [5.0; 5],
+            // Past fresh_for, but within expire_after
+            fetched_at: Instant::now() - Duration::from_secs(3601),
+            forecast: MetricSet {
+                temperature: Some([1.0; 5]),
+                ..Default::default()
+            },
         };
 
         service.cache.write().await.insert(pos, entry);
 
-        let sum = service.forecast(pos).await.unwrap().iter().sum::<f32>();
+        let (forecast, warnings) = service.forecast(pos).await.unwrap();
+
+        assert_eq!(
+            forecast.temperature.unwrap(),
+            [1.0; 5],
+            "should serve the stale entry immediately"
+        );
+        assert!(warnings.is_empty());
+
+        // Wait for the spawned refresh to land, then check it updated the cache
+        let refreshed = poll_until(|| {
+            service
+                .cache
+                .try_read()
+                .ok()
+                .and_then(|cache| cache.get(&pos).map(|entry| entry.forecast.temperature))
+                .flatten()
+                .filter(|series| *series != [1.0; 5])
+        })
+        .await;
+
+        assert_eq!(refreshed, [9.0, 10.0, 11.0, 12.0, 13.0]);
+    }
+
+    #[rocket::async_test]
+    async fn service_dedups_concurrent_refreshes_for_the_same_position() {
+        let service = service(Vec::from([erroneous("shouldn't be called")]));
+        let pos = CITIES.find("US", "chicago").unwrap();
+
+        // Pretend a refresh for this position is already in flight
+        service.refreshing.write().await.insert(pos);
+
+        // A second stale hit must not spawn another refresh for the same position
+        service.maybe_spawn_refresh(pos).await;
+
+        assert!(
+            service.refreshing.read().await.contains(&pos),
+            "the in-flight marker must be left untouched by the skipped call"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn service_refetches_synchronously_past_hard_expiry() {
+        let service = service(Vec::from([stub(2.0)]));
+        let pos = CITIES.find("US", "chicago").unwrap();
+
+        let entry = CacheEntry {
+            fetched_at: Instant::now() - Duration::from_secs(7201),
+            forecast: MetricSet {
+                temperature: Some([1.0; 5]),
+                ..Default::default()
+            },
+        };
+
+        service.cache.write().await.insert(pos, entry);
+
+        let (forecast, _) = service.forecast(pos).await.unwrap();
+
+        assert_eq!(
+            forecast.temperature.unwrap()[0],
+            2.0,
+            "expired entry must not be served"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn service_degrades_on_partial_failure() {
+        let service = service(Vec::from([stub(4.0), erroneous("rate limited")]));
+        let pos = CITIES.find("US", "chicago").unwrap();
+
+        let (forecast, warnings) = service.forecast(pos).await.unwrap();
+
+        assert_eq!(
+            forecast.temperature.unwrap()[0],
+            4.0,
+            "should average only the surviving provider"
+        );
+        assert_eq!(warnings.len(), 1);
+        // Warnings must never echo the raw upstream error - it may carry a
+        // provider API key in its request URL
+        assert_eq!(warnings[0], "fake: request failed");
+    }
+
+    #[rocket::async_test]
+    async fn service_does_not_leak_raw_provider_errors_into_warnings() {
+        let service = service(Vec::from([
+            stub(4.0),
+            erroneous("appid=super-secret-key"),
+        ]));
+        let pos = CITIES.find("US", "chicago").unwrap();
+
+        let (_, warnings) = service.forecast(pos).await.unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            !warnings[0].contains("super-secret-key"),
+            "warning must not leak provider error details: {}",
+            warnings[0]
+        );
+        assert_eq!(warnings[0], "fake: request failed");
+    }
+
+    #[rocket::async_test]
+    async fn service_fails_when_every_provider_fails() {
+        let service = service(Vec::from([erroneous("down")]));
+        let pos = CITIES.find("US", "chicago").unwrap();
+
+        let err = service.forecast(pos).await.unwrap_err();
+
+        assert!(format!("{:#}", err).contains("every provider failed"));
+    }
+
+    #[rocket::async_test]
+    async fn service_averages_precipitation_on_a_common_fraction_scale() {
+        // Providers must normalize precipitation to a 0-1 fraction before it
+        // reaches the accumulator; mixing 0.42 and 0.73 should average to
+        // 0.575, not the nonsense ~36.7 a leftover 0-100 scale would produce
+        let service = service(Vec::from([precip_stub(0.42), precip_stub(0.73)]));
+        let pos = CITIES.find("US", "chicago").unwrap();
+
+        let (forecast, _) = service.forecast(pos).await.unwrap();
+        let precipitation = forecast.precipitation.unwrap();
+
+        assert!(
+            precipitation.iter().all(|&p| (0.0..=1.0).contains(&p)),
+            "averaged precipitation left the 0-1 fraction scale: {:?}",
+            precipitation
+        );
+        assert!(
+            (precipitation[0] - 0.575).abs() < 1e-6,
+            "expected the average of 0.42 and 0.73, got {}",
+            precipitation[0]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn resolve_address_uses_the_geocode_cache() {
+        let pos = CITIES.find("US", "chicago").unwrap();
+        let service = Service::new(
+            Vec::from([stub(1.0)]),
+            geocoder::fake::stub(pos),
+            FRESH_FOR,
+            EXPIRE_AFTER,
+        );
+
+        let resolved = service.resolve_address("Some Address").await.unwrap();
+        assert_eq!(resolved.as_lat_lon(), pos.as_lat_lon());
+
+        // The normalized address should now be cached, independent of case/whitespace
+        assert!(service
+            .geocode_cache
+            .read()
+            .await
+            .contains_key("some address"));
+    }
+
+    #[rocket::async_test]
+    async fn resolve_address_propagates_geocoder_errors() {
+        let service = Service::new(
+            Vec::from([stub(1.0)]),
+            geocoder::fake::erroneous("address not found"),
+            FRESH_FOR,
+            EXPIRE_AFTER,
+        );
 
-        assert_eq!(sum, 25.0, "did not use cache");
+        let err = service.resolve_address("nowhere").await.unwrap_err();
+        assert!(format!("{:#}", err).contains("address not found"));
     }
 }